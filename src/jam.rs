@@ -1,4 +1,12 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
 use clap::Parser;
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 
 #[derive(Parser, Debug)]
@@ -31,6 +39,38 @@ pub struct Args {
     /// Maximum mirrors to leave uncommented in mirrorlist
     #[arg(long, short = 'n')]
     pub maximum_mirrors: Option<usize>,
+
+    /// Only keep mirrors resolving into these CIDR networks or named sets
+    /// (e.g. "10.0.0.0/8", "private", "none 192.0.2.0/24"). Space/comma separated.
+    #[arg(long, value_parser = validate_network_spec)]
+    pub allow_ips: Option<String>,
+
+    /// Drop mirrors resolving into these CIDR networks or named sets
+    /// (e.g. "private", "cgnat 240.0.0.0/4"). Space/comma separated.
+    #[arg(long, value_parser = validate_network_spec)]
+    pub block_ips: Option<String>,
+
+    /// Restrict to hosts matching a glob pattern, where `*` matches a single
+    /// label (e.g. "*.kernel.org")
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Restrict to a port: a fixed number, `*` for any, or "default"
+    #[arg(long, value_parser = validate_port_spec)]
+    pub port: Option<String>,
+
+    /// Benchmark surviving mirrors and re-rank them by measured throughput
+    /// instead of the API score
+    #[arg(long, default_value_t = false)]
+    pub benchmark: bool,
+
+    /// Discard mirrors slower than this many bytes/sec (implies --benchmark)
+    #[arg(long)]
+    pub speed_limit: Option<f64>,
+
+    /// Number of mirrors to benchmark concurrently (defaults to 16)
+    #[arg(long)]
+    pub benchmark_concurrency: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -68,6 +108,129 @@ fn maybe_absent_list<T: PartialEq>(argument: &Vec<T>, given: &T) -> bool {
     return argument.contains(&given);
 }
 
+/// A mirror URL broken into the pieces we actually filter on. An IPv6 literal
+/// host is stored without its surrounding brackets.
+#[derive(Debug, PartialEq)]
+struct ParsedUrl {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+/// The well-known port for a scheme, used when a URL leaves the port implicit.
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        "rsync" => Some(873),
+        "ftp" => Some(21),
+        _ => None,
+    }
+}
+
+/// Split a mirror URL into scheme/host/port. Returns `None` when the authority
+/// is malformed (missing scheme, empty host, unterminated IPv6 literal, or a
+/// port that is not a number).
+fn parse_url(url: &str) -> Option<ParsedUrl> {
+    let (scheme, rest) = url.split_once("://")?;
+    if scheme.is_empty() {
+        return None;
+    }
+
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+
+    let (host, port_str) = if let Some(after) = authority.strip_prefix('[') {
+        // Bracketed IPv6 literal, optionally followed by `:port`.
+        let (host, tail) = after.split_once(']')?;
+        let port = match tail.strip_prefix(':') {
+            Some(port) => Some(port),
+            None if tail.is_empty() => None,
+            None => return None,
+        };
+        (host, port)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    let port = match port_str {
+        Some(port) => Some(port.parse().ok()?),
+        None => None,
+    };
+
+    Some(ParsedUrl {
+        scheme: scheme.to_string(),
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// Match a glob-style host pattern against a host. The pattern and host are
+/// split on `.` and compared label-by-label; a `*` segment matches exactly one
+/// label, so `*.kernel.org` matches `mirrors.kernel.org` but not `kernel.org`.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let host: Vec<&str> = host.split('.').collect();
+
+    if pattern.len() != host.len() {
+        return false;
+    }
+
+    pattern
+        .iter()
+        .zip(host.iter())
+        .all(|(p, h)| *p == "*" || p == h)
+}
+
+/// The port requirement expressed by `--port`.
+#[derive(Debug, PartialEq)]
+enum PortReq {
+    /// The URL must leave the port implicit (the scheme's default).
+    Default,
+    /// Any port is acceptable (`*`).
+    Any,
+    /// A specific port number.
+    Fixed(u16),
+}
+
+impl PortReq {
+    fn parse(raw: &str) -> Option<PortReq> {
+        match raw {
+            "*" => Some(PortReq::Any),
+            "default" => Some(PortReq::Default),
+            other => other.parse().ok().map(PortReq::Fixed),
+        }
+    }
+
+    fn matches(&self, parsed: &ParsedUrl) -> bool {
+        match self {
+            PortReq::Any => true,
+            PortReq::Default => parsed.port.is_none(),
+            PortReq::Fixed(port) => {
+                parsed.port.or_else(|| default_port(&parsed.scheme)) == Some(*port)
+            }
+        }
+    }
+}
+
+/// Validate a `--port` value at argument-parse time so a malformed port surfaces
+/// as a clean clap error rather than a panic deeper in the pipeline.
+fn validate_port_spec(raw: &str) -> Result<String, String> {
+    match PortReq::parse(raw) {
+        Some(_) => Ok(raw.to_string()),
+        None => Err(format!(
+            "invalid port `{}`: expected a number, `*`, or `default`",
+            raw
+        )),
+    }
+}
+
 fn ip_filter(args: (bool, bool), given: (bool, bool)) -> bool {
     if args.0 && !given.0 {
         return false;
@@ -80,7 +243,270 @@ fn ip_filter(args: (bool, bool), given: (bool, bool)) -> bool {
     true
 }
 
+/// Expand a predefined, named set of special-use networks into the concrete
+/// CIDR blocks it covers. Returns `None` for an unknown name so the caller can
+/// fall back to parsing the token as a literal CIDR.
+fn predefined_set(name: &str) -> Option<Vec<IpNetwork>> {
+    let cidrs: &[&str] = match name {
+        "loopback" => &["127.0.0.0/8", "::1/128"],
+        "link-local" => &["169.254.0.0/16", "fe80::/10"],
+        "private" => &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"],
+        "cgnat" => &["100.64.0.0/10"],
+        "reserved" => &["240.0.0.0/4"],
+        "special" => &["192.0.0.0/24", "2001::/23"],
+        _ => return None,
+    };
+
+    Some(cidrs.iter().map(|c| c.parse().unwrap()).collect())
+}
+
+/// A set of networks an address can be tested against. `All` matches every
+/// address and is the allow set's default when `--allow-ips` is not supplied;
+/// the block set defaults to an empty `Only`.
+#[derive(Debug)]
+enum NetworkSet {
+    All,
+    Only(Vec<IpNetwork>),
+}
+
+impl NetworkSet {
+    fn contains(&self, addr: &IpAddr) -> bool {
+        match self {
+            NetworkSet::All => true,
+            NetworkSet::Only(nets) => nets.iter().any(|n| n.contains(*addr)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, NetworkSet::Only(nets) if nets.is_empty())
+    }
+}
+
+/// Wraps an allow set and a block set parsed from the command line, together
+/// with a per-host resolution cache so repeated mirrors don't hit DNS twice.
+#[derive(Debug)]
+pub struct IpFilter {
+    allow: NetworkSet,
+    block: NetworkSet,
+    cache: RefCell<HashMap<String, Option<Vec<IpAddr>>>>,
+}
+
+impl IpFilter {
+    pub fn from_args(args: &Args) -> IpFilter {
+        IpFilter {
+            allow: parse_set(&args.allow_ips, NetworkSet::All),
+            block: parse_set(&args.block_ips, NetworkSet::Only(Vec::new())),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve `host` to its A/AAAA records, caching the result. A host that
+    /// fails to resolve is remembered as `None` so we don't retry it.
+    fn resolve(&self, host: &str) -> Option<Vec<IpAddr>> {
+        if let Some(cached) = self.cache.borrow().get(host) {
+            return cached.clone();
+        }
+
+        // `ToSocketAddrs` needs a port; any value works for an address lookup.
+        let resolved = (host, 0u16)
+            .to_socket_addrs()
+            .ok()
+            .map(|addrs| addrs.map(|s| s.ip()).collect::<Vec<_>>())
+            .filter(|addrs: &Vec<IpAddr>| !addrs.is_empty());
+
+        self.cache
+            .borrow_mut()
+            .insert(host.to_string(), resolved.clone());
+        resolved
+    }
+
+    /// Whether any filtering is actually requested. When the allow set is `All`
+    /// and the block set is empty the filter is a no-op, so callers can skip
+    /// resolving hosts entirely.
+    fn is_active(&self) -> bool {
+        !matches!(self.allow, NetworkSet::All) || !self.block.is_empty()
+    }
+
+    /// A host passes when at least one of its addresses is in the allow set and
+    /// none of its addresses is in the block set. Hosts that fail to resolve are
+    /// dropped. When no filtering is requested this returns `true` without
+    /// resolving, so the default code path does no DNS lookups.
+    fn allows_host(&self, host: &str) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+
+        let addrs = match self.resolve(host) {
+            Some(addrs) => addrs,
+            None => return false,
+        };
+
+        if addrs.iter().any(|a| self.block.contains(a)) {
+            return false;
+        }
+
+        addrs.iter().any(|a| self.allow.contains(a))
+    }
+}
+
+/// Validate a network spec at argument-parse time so a malformed CIDR token
+/// surfaces as a clean clap error rather than a panic deeper in the pipeline.
+fn validate_network_spec(raw: &str) -> Result<String, String> {
+    for token in raw.split([' ', ',']).filter(|t| !t.is_empty()) {
+        match token {
+            "all" | "none" => {}
+            other if predefined_set(other).is_some() => {}
+            other => {
+                other
+                    .parse::<IpNetwork>()
+                    .map_err(|e| format!("invalid network `{}`: {}", other, e))?;
+            }
+        }
+    }
+
+    Ok(raw.to_string())
+}
+
+/// Parse a space/comma-separated list of CIDR networks and named sets. `all`
+/// widens the set to everything, `none` starts from an empty allow set, and any
+/// other token is expanded as a named set or parsed as a literal CIDR. Tokens
+/// are validated up front by [`validate_network_spec`], so parsing cannot fail
+/// here.
+fn parse_set(raw: &Option<String>, default: NetworkSet) -> NetworkSet {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return default,
+    };
+
+    let mut nets: Vec<IpNetwork> = Vec::new();
+    for token in raw.split([' ', ',']).filter(|t| !t.is_empty()) {
+        match token {
+            "all" => return NetworkSet::All,
+            "none" => nets.clear(),
+            other => match predefined_set(other) {
+                Some(set) => nets.extend(set),
+                None => nets.push(other.parse().expect("validated by validate_network_spec")),
+            },
+        }
+    }
+
+    NetworkSet::Only(nets)
+}
+
+/// A small, well-known file served by every Arch mirror, used to probe the
+/// client's real path to the mirror.
+const BENCHMARK_FILE: &str = "core/os/x86_64/core.db";
+
+/// Default number of mirrors probed at once when `--benchmark-concurrency` is
+/// not given.
+const DEFAULT_BENCHMARK_CONCURRENCY: usize = 16;
+
+/// How long a single benchmark fetch is allowed to take before it is treated as
+/// a failure.
+const BENCHMARK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of probing one mirror: effective download throughput in
+/// bytes/sec and the time until the response headers arrived. That latency
+/// includes connection setup, the request and the server's reply; connection
+/// setup is not timed on its own.
+#[derive(Debug)]
+struct Benchmark {
+    throughput: f64,
+    latency: Duration,
+}
+
+/// Download `BENCHMARK_FILE` from a mirror and measure how fast it comes back.
+/// Returns `None` if the fetch times out, errors, or yields no data.
+fn benchmark_mirror(client: &reqwest::blocking::Client, base: &str) -> Option<Benchmark> {
+    let target = format!("{}{}", base, BENCHMARK_FILE);
+
+    let start = Instant::now();
+    let response = client.get(&target).send().ok()?.error_for_status().ok()?;
+    let latency = start.elapsed();
+
+    let body = response.bytes().ok()?;
+    let elapsed = start.elapsed().as_secs_f64();
+    if body.is_empty() || elapsed <= 0.0 {
+        return None;
+    }
+
+    Some(Benchmark {
+        throughput: body.len() as f64 / elapsed,
+        latency,
+    })
+}
+
+/// Benchmark the http(s) mirrors over a bounded worker pool, drop the ones that
+/// fail or fall below `--speed-limit`, and return them sorted fastest first.
+/// `reqwest` only speaks http(s), so rsync/ftp mirrors are left on their API
+/// score ordering and appended after the benchmarked ones rather than dropped.
+fn benchmark_mirrors(mirrors: Vec<Url>, args: &Args) -> Vec<Url> {
+    let (mirrors, mut others): (Vec<Url>, Vec<Url>) = mirrors
+        .into_iter()
+        .partition(|m| m.protocol == "http" || m.protocol == "https");
+
+    others.sort_by(|a, b| a.score.unwrap().partial_cmp(&b.score.unwrap()).unwrap());
+
+    let concurrency = args
+        .benchmark_concurrency
+        .unwrap_or(DEFAULT_BENCHMARK_CONCURRENCY)
+        .max(1)
+        .min(mirrors.len().max(1));
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(BENCHMARK_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client");
+
+    let queue: Mutex<VecDeque<usize>> = Mutex::new((0..mirrors.len()).collect());
+    let results: Mutex<HashMap<usize, Benchmark>> = Mutex::new(HashMap::new());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().pop_front();
+                let index = match next {
+                    Some(index) => index,
+                    None => break,
+                };
+
+                if let Some(bench) = benchmark_mirror(&client, &mirrors[index].url) {
+                    results.lock().unwrap().insert(index, bench);
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    let minimum = args.speed_limit.unwrap_or(0.0);
+
+    let mut ranked: Vec<(Url, Benchmark)> = mirrors
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, mirror)| {
+            let bench = results.remove(&index)?;
+            (bench.throughput >= minimum).then_some((mirror, bench))
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        b.1.throughput
+            .partial_cmp(&a.1.throughput)
+            .unwrap()
+            .then(a.1.latency.cmp(&b.1.latency))
+    });
+
+    let mut mirrors: Vec<Url> = ranked.into_iter().map(|(mirror, _)| mirror).collect();
+    mirrors.append(&mut others);
+    mirrors
+}
+
 pub fn process_mirrors(res: ApiResponse, args: &Args) -> Vec<Url> {
+    let ip_filters = IpFilter::from_args(args);
+    let port_req = args
+        .port
+        .as_deref()
+        .map(|p| PortReq::parse(p).expect("validated by validate_port_spec"));
     let mut mirrors: Vec<Url> = res
         .urls
         .into_iter()
@@ -98,7 +524,35 @@ pub fn process_mirrors(res: ApiResponse, args: &Args) -> Vec<Url> {
                 && m.duration_avg.unwrap() + m.duration_stddev.unwrap() <= 1.0
         })
         .filter(|m| ip_filter((args.require_ipv4, args.require_ipv6), (m.ipv4, m.ipv6)))
+        .filter(|m| {
+            // Reject mirrors whose authority can't be parsed rather than
+            // silently keeping them.
+            let parsed = match parse_url(&m.url) {
+                Some(parsed) => parsed,
+                None => return false,
+            };
+
+            if let Some(pattern) = &args.host {
+                if !host_matches(pattern, &parsed.host) {
+                    return false;
+                }
+            }
+
+            if let Some(port) = &port_req {
+                if !port.matches(&parsed) {
+                    return false;
+                }
+            }
+
+            ip_filters.allows_host(&parsed.host)
+        })
         .collect();
+    // Re-rank by measured throughput when asked; otherwise preserve the
+    // historical behaviour of trusting the API's score.
+    if args.benchmark || args.speed_limit.is_some() {
+        return benchmark_mirrors(mirrors, args);
+    }
+
     mirrors.sort_by(|a, b| a.score.unwrap().partial_cmp(&b.score.unwrap()).unwrap());
     mirrors
 }
@@ -197,6 +651,106 @@ mod tests {
         assert_eq!(ip_filter(args, has_neither), true);
     }
 
+    #[test]
+    fn parse_url_splits_authority() {
+        let parsed = parse_url("rsync://mirror.example.com:873/arch").unwrap();
+        assert_eq!(parsed.scheme, "rsync");
+        assert_eq!(parsed.host, "mirror.example.com");
+        assert_eq!(parsed.port, Some(873));
+    }
+
+    #[test]
+    fn parse_url_no_port() {
+        let parsed = parse_url("https://mirror.example.com/").unwrap();
+        assert_eq!(parsed.host, "mirror.example.com");
+        assert_eq!(parsed.port, None);
+    }
+
+    #[test]
+    fn parse_url_bracketed_ipv6() {
+        let parsed = parse_url("https://[2001:db8::1]:8443/").unwrap();
+        assert_eq!(parsed.host, "2001:db8::1");
+        assert_eq!(parsed.port, Some(8443));
+
+        let no_port = parse_url("https://[2001:db8::1]/").unwrap();
+        assert_eq!(no_port.host, "2001:db8::1");
+        assert_eq!(no_port.port, None);
+    }
+
+    #[test]
+    fn parse_url_rejects_malformed() {
+        assert!(parse_url("not-a-url").is_none());
+        assert!(parse_url("https:///path").is_none());
+        assert!(parse_url("https://host:notaport/").is_none());
+    }
+
+    #[test]
+    fn host_matches_wildcard_label() {
+        assert_eq!(host_matches("*.kernel.org", "mirrors.kernel.org"), true);
+        assert_eq!(host_matches("*.kernel.org", "kernel.org"), false);
+        assert_eq!(host_matches("*.kernel.org", "a.b.kernel.org"), false);
+        assert_eq!(host_matches("mirrors.kernel.org", "mirrors.kernel.org"), true);
+    }
+
+    #[test]
+    fn port_req_parses_variants() {
+        assert_eq!(PortReq::parse("*"), Some(PortReq::Any));
+        assert_eq!(PortReq::parse("default"), Some(PortReq::Default));
+        assert_eq!(PortReq::parse("443"), Some(PortReq::Fixed(443)));
+        assert_eq!(PortReq::parse("nope"), None);
+    }
+
+    #[test]
+    fn port_req_matches() {
+        let explicit = parse_url("https://host.example:8443/").unwrap();
+        let implicit = parse_url("https://host.example/").unwrap();
+
+        assert_eq!(PortReq::Any.matches(&explicit), true);
+        assert_eq!(PortReq::Default.matches(&implicit), true);
+        assert_eq!(PortReq::Default.matches(&explicit), false);
+        assert_eq!(PortReq::Fixed(443).matches(&implicit), true);
+        assert_eq!(PortReq::Fixed(8443).matches(&explicit), true);
+        assert_eq!(PortReq::Fixed(443).matches(&explicit), false);
+    }
+
+    #[test]
+    fn predefined_set_expands_private() {
+        let set = predefined_set("private").unwrap();
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn predefined_set_unknown_is_none() {
+        assert!(predefined_set("nonsense").is_none());
+    }
+
+    #[test]
+    fn parse_set_none_default_allows_all() {
+        let set = parse_set(&None, NetworkSet::All);
+        assert!(set.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_set_narrow_range() {
+        let set = parse_set(&Some(String::from("none 192.0.2.0/24")), NetworkSet::All);
+        assert!(set.contains(&"192.0.2.5".parse().unwrap()));
+        assert!(!set.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_set_named_private() {
+        let set = parse_set(&Some(String::from("private")), NetworkSet::Only(Vec::new()));
+        assert!(set.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(set.contains(&"192.168.1.1".parse().unwrap()));
+        assert!(!set.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_set_all_keyword() {
+        let set = parse_set(&Some(String::from("none 10.0.0.0/8 all")), NetworkSet::Only(Vec::new()));
+        assert!(set.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
     #[test]
     fn test_main() {
         let res: ApiResponse = ApiResponse {
@@ -284,6 +838,13 @@ mod tests {
             maximum_mirrors: None,
             output: None,
             country: Some(String::from("US")),
+            allow_ips: None,
+            block_ips: None,
+            host: None,
+            port: None,
+            benchmark: false,
+            speed_limit: None,
+            benchmark_concurrency: None,
         };
 
         let mirrors = process_mirrors(res, &args);